@@ -18,6 +18,7 @@
 
 mod types;
 mod sink;
+mod token_sink;
 
 #[cfg(debug_assertions)]
 #[global_allocator]
@@ -27,19 +28,105 @@ use types::*;
 use std::cell::Cell;
 use std::os::raw::{c_uchar, c_void};
 
-use html5ever::{parse_document, parse_fragment, QualName, LocalName, ns, ParseOpts, Parser};
+use html5ever::{parse_document, parse_fragment, ParseOpts, Parser};
 use html5ever::tendril::{TendrilSink, StrTendril};
 use html5ever::interface::tree_builder::QuirksMode;
+use html5ever::tokenizer::{BufferQueue, Tokenizer, TokenizerOpts, TokenizerResult};
+use encoding_rs::{Decoder, Encoding, UTF_8};
+
+// Resolves a declared charset label (e.g. from a Content-Type header) to an
+// encoding, ignoring empty/unrecognized labels.
+fn label_to_encoding(declared: &StringSlice) -> Option<&'static Encoding> {
+    if declared.ptr.is_null() || declared.len == 0 {
+        return None;
+    }
+    let label = unsafe { std::slice::from_raw_parts(declared.ptr, declared.len) };
+    return Encoding::for_label(label);
+}
+
+// Sniffs an encoding from the first bytes of a document: a BOM, or a
+// `<meta charset=...>`/`<meta http-equiv=Content-Type content="...charset=...">`
+// declaration, defaulting to UTF-8 when neither is present.
+fn sniff_encoding(bytes: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _bom_len)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    if let Some(encoding) = sniff_meta_charset(bytes) {
+        return encoding;
+    }
+    return UTF_8;
+}
+
+// How many leading bytes sniff_meta_charset looks at for a <meta charset>
+// declaration; also how many head bytes the streaming parser holds back
+// before committing to an encoding (see StreamingParser::sniff_buffer).
+const SNIFF_WINDOW: usize = 1024;
+
+fn sniff_meta_charset(bytes: &[u8]) -> Option<&'static Encoding> {
+    let window = &bytes[..bytes.len().min(SNIFF_WINDOW)];
+    let lower = window.to_ascii_lowercase();
+    let start = lower.windows(8).position(|w| w == b"charset=")? + 8;
+    let rest = &window[start..];
+    let rest = rest.strip_prefix(b"\"").or_else(|| rest.strip_prefix(b"'")).unwrap_or(rest);
+    let end = rest.iter()
+        .position(|b| matches!(b, b'"' | b'\'' | b'>' | b' ' | b'\t' | b'\n' | b'\r'))
+        .unwrap_or(rest.len());
+    return Encoding::for_label(&rest[..end]);
+}
+
+// Decodes a whole, non-streamed buffer to UTF-8, honouring a declared
+// encoding when given and otherwise sniffing one from `bytes` itself.
+fn decode_full(bytes: &[u8], declared: &StringSlice) -> String {
+    let encoding = label_to_encoding(declared).unwrap_or_else(|| sniff_encoding(bytes));
+    let (decoded, _actual_encoding, _had_errors) = encoding.decode(bytes);
+    return decoded.into_owned();
+}
+
+// Decodes as much valid UTF-8 as possible from `bytes`, replacing malformed
+// sequences with U+FFFD, and returns any trailing incomplete sequence (at
+// most 3 bytes) to be retried once more bytes are fed.
+fn decode_available_utf8(bytes: &[u8]) -> (String, Vec<u8>) {
+    let mut out = String::with_capacity(bytes.len());
+    let mut rest = bytes;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(s) => {
+                out.push_str(s);
+                return (out, Vec::new());
+            },
+            Err(e) => {
+                let valid_len = e.valid_up_to();
+                out.push_str(unsafe { std::str::from_utf8_unchecked(&rest[..valid_len]) });
+                match e.error_len() {
+                    // A sequence that can never become valid, however much
+                    // more input follows: replace it and keep decoding.
+                    Some(bad_len) => {
+                        out.push('\u{FFFD}');
+                        rest = &rest[valid_len + bad_len..];
+                    },
+                    // The tail might be the start of a sequence split across
+                    // the chunk boundary: keep it for the next feed.
+                    None => {
+                        return (out, rest[valid_len..].to_vec());
+                    },
+                }
+            },
+        }
+    }
+}
 
 #[no_mangle]
 pub extern "C" fn html5ever_parse_document(
     html: *mut c_uchar,
     len: usize,
+    declared_encoding: StringSlice,
     document: Ref,
     ctx: Ref,
     create_element_callback: CreateElementCallback,
     get_data_callback: GetDataCallback,
     append_callback: AppendCallback,
+    insert_before_sibling_callback: InsertBeforeSiblingCallback,
+    append_based_on_parent_node_callback: AppendBasedOnParentNodeCallback,
     parse_error_callback: ParseErrorCallback,
     pop_callback: PopCallback,
     create_comment_callback: CreateCommentCallback,
@@ -48,6 +135,7 @@ pub extern "C" fn html5ever_parse_document(
     get_template_contents_callback: GetTemplateContentsCallback,
     remove_from_parent_callback: RemoveFromParentCallback,
     reparent_children_callback: ReparentChildrenCallback,
+    filter_callback: Option<FilterCallback>,
 ) -> () {
     if html.is_null() || len == 0 {
         return ();
@@ -62,6 +150,8 @@ pub extern "C" fn html5ever_parse_document(
         quirks_mode: Cell::new(QuirksMode::NoQuirks),
         pop_callback: pop_callback,
         append_callback: append_callback,
+        insert_before_sibling_callback: insert_before_sibling_callback,
+        append_based_on_parent_node_callback: append_based_on_parent_node_callback,
         get_data_callback: get_data_callback,
         parse_error_callback: parse_error_callback,
         create_element_callback: create_element_callback,
@@ -71,23 +161,30 @@ pub extern "C" fn html5ever_parse_document(
         get_template_contents_callback: get_template_contents_callback,
         remove_from_parent_callback: remove_from_parent_callback,
         reparent_children_callback: reparent_children_callback,
+        filter_callback: filter_callback,
     };
 
     let bytes = unsafe { std::slice::from_raw_parts(html, len) };
+    let html_str = decode_full(bytes, &declared_encoding);
     parse_document(sink, Default::default())
         .from_utf8()
-        .one(bytes);
+        .one(html_str.as_bytes());
 }
 
 #[no_mangle]
 pub extern "C" fn html5ever_parse_fragment(
     html: *mut c_uchar,
     len: usize,
+    declared_encoding: StringSlice,
+    context_name: CQualName,
+    context_scripting_enabled: u8,
     document: Ref,
     ctx: Ref,
     create_element_callback: CreateElementCallback,
     get_data_callback: GetDataCallback,
     append_callback: AppendCallback,
+    insert_before_sibling_callback: InsertBeforeSiblingCallback,
+    append_based_on_parent_node_callback: AppendBasedOnParentNodeCallback,
     parse_error_callback: ParseErrorCallback,
     pop_callback: PopCallback,
     create_comment_callback: CreateCommentCallback,
@@ -96,6 +193,7 @@ pub extern "C" fn html5ever_parse_fragment(
     get_template_contents_callback: GetTemplateContentsCallback,
     remove_from_parent_callback: RemoveFromParentCallback,
     reparent_children_callback: ReparentChildrenCallback,
+    filter_callback: Option<FilterCallback>,
 ) -> () {
     if html.is_null() || len == 0 {
         return ();
@@ -110,6 +208,8 @@ pub extern "C" fn html5ever_parse_fragment(
         quirks_mode: Cell::new(QuirksMode::NoQuirks),
         pop_callback: pop_callback,
         append_callback: append_callback,
+        insert_before_sibling_callback: insert_before_sibling_callback,
+        append_based_on_parent_node_callback: append_based_on_parent_node_callback,
         get_data_callback: get_data_callback,
         parse_error_callback: parse_error_callback,
         create_element_callback: create_element_callback,
@@ -119,17 +219,20 @@ pub extern "C" fn html5ever_parse_fragment(
         get_template_contents_callback: get_template_contents_callback,
         remove_from_parent_callback: remove_from_parent_callback,
         reparent_children_callback: reparent_children_callback,
+        filter_callback: filter_callback,
     };
 
     let bytes = unsafe { std::slice::from_raw_parts(html, len) };
+    let html_str = decode_full(bytes, &declared_encoding);
+    let context = unsafe { context_name.to_qual_name() };
     parse_fragment(
         sink, Default::default(),
-        QualName::new(None, ns!(html), LocalName::from("body")),
+        context,
         vec![],     // attributes
-        false,      // context_element_allows_scripting
+        context_scripting_enabled != 0,
     )
         .from_utf8()
-        .one(bytes);
+        .one(html_str.as_bytes());
 }
 
 #[no_mangle]
@@ -160,6 +263,42 @@ pub extern "C" fn html5ever_attribute_iterator_count(c_iter: *const c_void) -> u
     return iter.vec.len();
 }
 
+// Renames the attribute at `index`, e.g. to turn `src` into `data-src` while
+// rewriting a tree. Returns 0 if `index` is out of bounds.
+#[no_mangle]
+pub extern "C" fn html5ever_attribute_iterator_set_name(
+    c_iter: *const c_void,
+    index: usize,
+    name: StringSlice,
+) -> u8 {
+    let iter: &mut CAttributeIterator = unsafe { &mut *(c_iter as *mut CAttributeIterator) };
+    if index >= iter.vec.len() {
+        return 0;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(name.ptr, name.len) };
+    let local = unsafe { std::str::from_utf8_unchecked(bytes) };
+    iter.vec[index].name.local = html5ever::LocalName::from(local);
+    return 1;
+}
+
+// Rewrites the value of the attribute at `index`. Returns 0 if `index` is
+// out of bounds.
+#[no_mangle]
+pub extern "C" fn html5ever_attribute_iterator_set_value(
+    c_iter: *const c_void,
+    index: usize,
+    value: StringSlice,
+) -> u8 {
+    let iter: &mut CAttributeIterator = unsafe { &mut *(c_iter as *mut CAttributeIterator) };
+    if index >= iter.vec.len() {
+        return 0;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(value.ptr, value.len) };
+    let value = unsafe { std::str::from_utf8_unchecked(bytes) };
+    iter.vec[index].value = StrTendril::from(value);
+    return 1;
+}
+
 #[cfg(debug_assertions)]
 #[repr(C)]
 pub struct Memory {
@@ -186,15 +325,35 @@ pub extern "C" fn html5ever_get_memory_usage() -> Memory {
 pub struct StreamingParser {
     arena: Box<typed_arena::Arena<sink::ElementData>>,
     parser: Box<dyn std::any::Any>,
+    // Declared up front if the caller knows the charset; otherwise resolved
+    // by sniffing once SNIFF_WINDOW head bytes (or the whole document, at
+    // finish()) have been buffered, and left None until then.
+    declared_encoding: Option<&'static Encoding>,
+    encoding: Option<&'static Encoding>,
+    // Bytes withheld from the parser until `encoding` is resolved: a <meta
+    // charset> can arrive split across feed() calls, so sniffing must see
+    // up to SNIFF_WINDOW head bytes (or end-of-document) before committing,
+    // rather than deciding off of whatever the first feed() call happened
+    // to contain.
+    sniff_buffer: Vec<u8>,
+    // UTF-8 fast path: bytes left over from a feed() call that ended mid
+    // multi-byte sequence, retried once more bytes arrive.
+    leftover: Vec<u8>,
+    // Non-UTF-8 path: encoding_rs keeps its own state across calls, so it
+    // doesn't need the leftover-byte bookkeeping above.
+    decoder: Option<Decoder>,
 }
 
 #[no_mangle]
 pub extern "C" fn html5ever_streaming_parser_create(
     document: Ref,
     ctx: Ref,
+    declared_encoding: StringSlice,
     create_element_callback: CreateElementCallback,
     get_data_callback: GetDataCallback,
     append_callback: AppendCallback,
+    insert_before_sibling_callback: InsertBeforeSiblingCallback,
+    append_based_on_parent_node_callback: AppendBasedOnParentNodeCallback,
     parse_error_callback: ParseErrorCallback,
     pop_callback: PopCallback,
     create_comment_callback: CreateCommentCallback,
@@ -203,6 +362,7 @@ pub extern "C" fn html5ever_streaming_parser_create(
     get_template_contents_callback: GetTemplateContentsCallback,
     remove_from_parent_callback: RemoveFromParentCallback,
     reparent_children_callback: ReparentChildrenCallback,
+    filter_callback: Option<FilterCallback>,
 ) -> *mut c_void {
     let arena = Box::new(typed_arena::Arena::new());
 
@@ -220,6 +380,8 @@ pub extern "C" fn html5ever_streaming_parser_create(
         quirks_mode: Cell::new(QuirksMode::NoQuirks),
         pop_callback: pop_callback,
         append_callback: append_callback,
+        insert_before_sibling_callback: insert_before_sibling_callback,
+        append_based_on_parent_node_callback: append_based_on_parent_node_callback,
         get_data_callback: get_data_callback,
         parse_error_callback: parse_error_callback,
         create_element_callback: create_element_callback,
@@ -229,6 +391,7 @@ pub extern "C" fn html5ever_streaming_parser_create(
         get_template_contents_callback: get_template_contents_callback,
         remove_from_parent_callback: remove_from_parent_callback,
         reparent_children_callback: reparent_children_callback,
+        filter_callback: filter_callback,
     };
 
     // Create a parser which implements TendrilSink for streaming parsing
@@ -237,11 +400,62 @@ pub extern "C" fn html5ever_streaming_parser_create(
     let streaming_parser = Box::new(StreamingParser {
         arena,
         parser: Box::new(parser),
+        declared_encoding: label_to_encoding(&declared_encoding),
+        encoding: None,
+        sniff_buffer: Vec::new(),
+        leftover: Vec::new(),
+        decoder: None,
     });
 
     return Box::into_raw(streaming_parser) as *mut c_void;
 }
 
+// Resolves `streaming_parser.encoding` once enough has been seen to trust
+// it: a declared encoding always wins immediately, otherwise bytes are
+// accumulated in `sniff_buffer` until there's a full SNIFF_WINDOW to sniff
+// (or `force`, for end-of-document). Returns the bytes that were withheld
+// pending this decision, ready to decode, once resolved.
+fn streaming_parser_resolve_encoding(
+    streaming_parser: &mut StreamingParser,
+    bytes: &[u8],
+    force: bool,
+) -> Option<Vec<u8>> {
+    streaming_parser.sniff_buffer.extend_from_slice(bytes);
+    let encoding = streaming_parser.declared_encoding.or_else(|| {
+        (force || streaming_parser.sniff_buffer.len() >= SNIFF_WINDOW)
+            .then(|| sniff_encoding(&streaming_parser.sniff_buffer))
+    })?;
+    if encoding != UTF_8 {
+        streaming_parser.decoder = Some(encoding.new_decoder());
+    }
+    streaming_parser.encoding = Some(encoding);
+    return Some(std::mem::take(&mut streaming_parser.sniff_buffer));
+}
+
+fn streaming_parser_decode_and_process(streaming_parser: &mut StreamingParser, bytes: &[u8]) {
+    // The Parser implements TendrilSink, so we can call process() on it
+    let parser = streaming_parser.parser
+        .downcast_mut::<Parser<sink::Sink>>()
+        .expect("Invalid parser type");
+
+    if let Some(decoder) = streaming_parser.decoder.as_mut() {
+        let mut decoded = String::with_capacity(bytes.len());
+        let _ = decoder.decode_to_string(bytes, &mut decoded, false);
+        if !decoded.is_empty() {
+            parser.process(StrTendril::from(decoded));
+        }
+        return;
+    }
+
+    let mut combined = std::mem::take(&mut streaming_parser.leftover);
+    combined.extend_from_slice(bytes);
+    let (decoded, leftover) = decode_available_utf8(&combined);
+    streaming_parser.leftover = leftover;
+    if !decoded.is_empty() {
+        parser.process(StrTendril::from(decoded));
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn html5ever_streaming_parser_feed(
     parser_ptr: *mut c_void,
@@ -255,18 +469,17 @@ pub extern "C" fn html5ever_streaming_parser_feed(
     let streaming_parser = unsafe { &mut *(parser_ptr as *mut StreamingParser) };
     let bytes = unsafe { std::slice::from_raw_parts(html, len) };
 
-    // Convert bytes to UTF-8 string
-    if let Ok(s) = std::str::from_utf8(bytes) {
-        let tendril = StrTendril::from(s);
-
-        // Feed the chunk to the parser
-        // The Parser implements TendrilSink, so we can call process() on it
-        let parser = streaming_parser.parser
-            .downcast_mut::<Parser<sink::Sink>>()
-            .expect("Invalid parser type");
-
-        parser.process(tendril);
+    if streaming_parser.encoding.is_none() {
+        match streaming_parser_resolve_encoding(streaming_parser, bytes, false) {
+            Some(buffered) => streaming_parser_decode_and_process(streaming_parser, &buffered),
+            // Not enough head bytes yet to trust the sniff; hold everything
+            // back until more arrives (or finish() forces a decision).
+            None => return,
+        }
+        return;
     }
+
+    streaming_parser_decode_and_process(streaming_parser, bytes);
 }
 
 #[no_mangle]
@@ -275,7 +488,33 @@ pub extern "C" fn html5ever_streaming_parser_finish(parser_ptr: *mut c_void) {
         return;
     }
 
-    let streaming_parser = unsafe { Box::from_raw(parser_ptr as *mut StreamingParser) };
+    let mut streaming_parser = unsafe { Box::from_raw(parser_ptr as *mut StreamingParser) };
+
+    if streaming_parser.encoding.is_none() {
+        // The whole document was shorter than SNIFF_WINDOW: force a
+        // decision from whatever we've buffered and process it now.
+        if let Some(buffered) = streaming_parser_resolve_encoding(&mut streaming_parser, &[], true) {
+            streaming_parser_decode_and_process(&mut streaming_parser, &buffered);
+        }
+    }
+
+    {
+        let parser = streaming_parser.parser
+            .downcast_mut::<Parser<sink::Sink>>()
+            .expect("Invalid parser type");
+
+        if let Some(decoder) = streaming_parser.decoder.as_mut() {
+            let mut decoded = String::new();
+            let _ = decoder.decode_to_string(&[], &mut decoded, true);
+            if !decoded.is_empty() {
+                parser.process(StrTendril::from(decoded));
+            }
+        } else if !streaming_parser.leftover.is_empty() {
+            // Whatever is left here is a genuinely incomplete trailing
+            // sequence; surface it rather than silently dropping it.
+            parser.process(StrTendril::from("\u{FFFD}"));
+        }
+    }
 
     // Extract and finish the parser
     let parser = streaming_parser.parser
@@ -300,3 +539,114 @@ pub extern "C" fn html5ever_streaming_parser_destroy(parser_ptr: *mut c_void) {
         let _ = Box::from_raw(parser_ptr as *mut StreamingParser);
     }
 }
+
+// Tokenizer-only streaming API.
+// Unlike html5ever_streaming_parser_*, this does not build a DOM: it hands
+// the raw token stream straight to C, for consumers (scanners, link
+// extractors, highlighters) that don't need full tree-building callbacks.
+pub struct CTokenizer {
+    tokenizer: Tokenizer<token_sink::TokenSinkImpl>,
+    queue: BufferQueue,
+    // Bytes left over from a feed() call that ended mid multi-byte
+    // sequence, retried once more bytes arrive (see decode_available_utf8).
+    leftover: Vec<u8>,
+}
+
+#[no_mangle]
+pub extern "C" fn html5ever_tokenizer_create(
+    ctx: Ref,
+    character_callback: TokenCharacterCallback,
+    start_tag_callback: TokenStartTagCallback,
+    end_tag_callback: TokenEndTagCallback,
+    comment_callback: TokenCommentCallback,
+    doctype_callback: TokenDoctypeCallback,
+    parse_error_callback: TokenParseErrorCallback,
+    eof_callback: TokenEofCallback,
+) -> *mut c_void {
+    let sink = token_sink::TokenSinkImpl {
+        ctx: ctx,
+        character_callback: character_callback,
+        start_tag_callback: start_tag_callback,
+        end_tag_callback: end_tag_callback,
+        comment_callback: comment_callback,
+        doctype_callback: doctype_callback,
+        parse_error_callback: parse_error_callback,
+        eof_callback: eof_callback,
+    };
+
+    let tokenizer = Tokenizer::new(sink, TokenizerOpts::default());
+
+    let c_tokenizer = Box::new(CTokenizer {
+        tokenizer: tokenizer,
+        queue: BufferQueue::default(),
+        leftover: Vec::new(),
+    });
+
+    return Box::into_raw(c_tokenizer) as *mut c_void;
+}
+
+#[no_mangle]
+pub extern "C" fn html5ever_tokenizer_feed(
+    tokenizer_ptr: *mut c_void,
+    html: *const c_uchar,
+    len: usize,
+) {
+    if tokenizer_ptr.is_null() || html.is_null() || len == 0 {
+        return;
+    }
+
+    let c_tokenizer = unsafe { &mut *(tokenizer_ptr as *mut CTokenizer) };
+    let bytes = unsafe { std::slice::from_raw_parts(html, len) };
+
+    let mut combined = std::mem::take(&mut c_tokenizer.leftover);
+    combined.extend_from_slice(bytes);
+    let (decoded, leftover) = decode_available_utf8(&combined);
+    c_tokenizer.leftover = leftover;
+    if !decoded.is_empty() {
+        c_tokenizer.queue.push_back(StrTendril::from(decoded).into());
+    }
+
+    loop {
+        match c_tokenizer.tokenizer.feed(&mut c_tokenizer.queue) {
+            TokenizerResult::Done => break,
+            // A script start tag was seen; the C side already decided
+            // what to do with it via the start-tag callback's return
+            // value, so just keep tokenizing.
+            TokenizerResult::Script(_) => continue,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn html5ever_tokenizer_finish(tokenizer_ptr: *mut c_void) {
+    if tokenizer_ptr.is_null() {
+        return;
+    }
+
+    let mut c_tokenizer = unsafe { Box::from_raw(tokenizer_ptr as *mut CTokenizer) };
+
+    if !c_tokenizer.leftover.is_empty() {
+        // Whatever is left here is a genuinely incomplete trailing
+        // sequence; surface it rather than silently dropping it.
+        c_tokenizer.queue.push_back(StrTendril::from("\u{FFFD}").into());
+        loop {
+            match c_tokenizer.tokenizer.feed(&mut c_tokenizer.queue) {
+                TokenizerResult::Done => break,
+                TokenizerResult::Script(_) => continue,
+            }
+        }
+    }
+
+    c_tokenizer.tokenizer.end();
+}
+
+#[no_mangle]
+pub extern "C" fn html5ever_tokenizer_destroy(tokenizer_ptr: *mut c_void) {
+    if tokenizer_ptr.is_null() {
+        return;
+    }
+
+    unsafe {
+        let _ = Box::from_raw(tokenizer_ptr as *mut CTokenizer);
+    }
+}
@@ -20,15 +20,55 @@ use std::ptr;
 use std::cell::Cell;
 use std::borrow::Cow;
 use std::os::raw::{c_void};
+use std::sync::OnceLock;
 
 use crate::types::*;
 
 use html5ever::tendril::{StrTendril};
-use html5ever::{Attribute, QualName};
+use html5ever::{ns, Attribute, LocalName, QualName};
 use html5ever::interface::tree_builder::{ElementFlags, NodeOrText, QuirksMode, TreeSink};
 
 type Arena<'arena> = &'arena typed_arena::Arena<ElementData>;
 
+// Sentinel Handle returned by create_element for an element the filter
+// callback dropped: append/append_before_sibling/etc. recognize it and
+// discard writes to it instead of forwarding them to the C side, so a
+// dropped element's subtree is silently discarded rather than attached to
+// a node that was never created.
+//
+// This is a non-null, non-zero magic address rather than null: several
+// callbacks legitimately return null for a real handle (e.g. create_comment,
+// get_template_contents when an element has no template contents), and those
+// must stay distinguishable from DROPPED -- is_dropped/child_node_is_dropped
+// would otherwise silently eat a genuine null handle from such a callback.
+// Handle contract: the C side must never return this exact value for a real
+// handle.
+const DROPPED: Ref = 1 as Ref;
+
+// Every handle-taking TreeSink method must check is_dropped(handle) before
+// touching it: get_data_callback only ever knew about handles the C side
+// actually created via create_element_callback, so calling it (or any other
+// callback) with DROPPED is UB. This sentinel QualName lets elem_name answer
+// for a dropped handle without that call, so html5ever's scope-check
+// algorithms (which walk the whole open-elements stack, dropped nodes
+// included) stay safe.
+//
+// Known limitation: every dropped element answers elem_name with this same
+// generic qname, so insertion-mode decisions that key off a dropped
+// container's own element type (e.g. foster-parenting around a dropped
+// <table>/<select>) fall back to generic handling rather than table-/
+// select-aware handling for the siblings that follow it. Acceptable for the
+// intended use (dropping <script>/<iframe>/ad subtrees, which don't
+// participate in those insertion modes); dropping a table/select is not
+// recommended until dropped elements get their own per-instance ElementData
+// instead of sharing this sentinel.
+fn dropped_qname() -> &'static QualName {
+    static DROPPED_QNAME: OnceLock<QualName> = OnceLock::new();
+    return DROPPED_QNAME.get_or_init(|| {
+        QualName::new(None, ns!(html), LocalName::from("dropped-element"))
+    });
+}
+
 // Made public so it can be used from lib.rs
 pub struct ElementData {
     pub qname: QualName,
@@ -50,6 +90,8 @@ pub struct Sink<'arena> {
     pub quirks_mode: Cell<QuirksMode>,
     pub pop_callback: PopCallback,
     pub append_callback: AppendCallback,
+    pub insert_before_sibling_callback: InsertBeforeSiblingCallback,
+    pub append_based_on_parent_node_callback: AppendBasedOnParentNodeCallback,
     pub get_data_callback: GetDataCallback,
     pub parse_error_callback: ParseErrorCallback,
     pub create_element_callback: CreateElementCallback,
@@ -60,6 +102,59 @@ pub struct Sink<'arena> {
     pub get_template_contents_callback: GetTemplateContentsCallback,
     pub remove_from_parent_callback: RemoveFromParentCallback,
     pub reparent_children_callback: ReparentChildrenCallback,
+    // None means no filtering: every element is kept as-is.
+    pub filter_callback: Option<FilterCallback>,
+}
+
+impl<'arena> Sink<'arena> {
+    // The child exists for the duration of the append_callback call,
+    // but sometimes the memory on the Zig side, in append_callback,
+    // is zeroed. If you try to refactor this code a bit, and do:
+    //   unsafe {
+    //       (self.append_callback)(self.ctx, *parent, CNodeOrText::create(child));
+    //   }
+    // Where CNodeOrText::create returns the property CNodeOrText,
+    // you'll occasionally see that zeroed memory. Makes no sense to
+    // me, but a far as I can tell, this version works.
+    //
+    // For AppendText, the CNodeOrText we build points into `child`'s
+    // StrTendril buffer, so `child` must still be alive when `f` (and
+    // whatever callback it invokes) runs. Building the struct and invoking
+    // `f` in the same scope, instead of returning the struct out of here,
+    // keeps that buffer alive for the duration of the call.
+    fn with_node_or_text<R>(child: NodeOrText<Ref>, f: impl FnOnce(CNodeOrText) -> R) -> R {
+        match child {
+            NodeOrText::AppendText(ref t) => {
+                let byte_slice = t.as_ref().as_bytes();
+                let static_slice: &'static [u8] = unsafe {
+                    std::mem::transmute(byte_slice)
+                };
+                f(CNodeOrText {
+                    tag: 1,
+                    node: ptr::null(),
+                    text: StringSlice { ptr: static_slice.as_ptr(), len: static_slice.len()},
+                })
+            },
+            NodeOrText::AppendNode(node) => {
+                f(CNodeOrText {
+                    tag: 0,
+                    node: node,
+                    text: StringSlice::default(),
+                })
+            }
+        }
+    }
+
+    fn is_dropped(r: &Ref) -> bool {
+        *r == DROPPED
+    }
+
+    fn child_node_is_dropped(child: &NodeOrText<Ref>) -> bool {
+        match child {
+            NodeOrText::AppendNode(node) => Self::is_dropped(node),
+            NodeOrText::AppendText(_) => false,
+        }
+    }
 }
 
 impl<'arena> TreeSink for Sink<'arena> {
@@ -95,34 +190,74 @@ impl<'arena> TreeSink for Sink<'arena> {
     }
 
     fn same_node(&self, x: &Ref, y: &Ref) -> bool {
+        // Two dropped handles are never the same node: each was discarded
+        // independently and never shared an identity on the C side.
+        if Self::is_dropped(x) || Self::is_dropped(y) {
+            return false;
+        }
         ptr::eq::<c_void>(*x, *y)
     }
 
     fn elem_name(&self, target: &Ref) -> Self::ElemName<'_> {
+        if Self::is_dropped(target) {
+            return dropped_qname();
+        }
         let opaque = unsafe { (self.get_data_callback)(*target) };
         let data = opaque as *mut ElementData;
         return unsafe { &(*data).qname };
     }
 
     fn get_template_contents(&self, target: &Ref) -> Ref {
+        if Self::is_dropped(target) {
+            return DROPPED;
+        }
         unsafe {
             return (self.get_template_contents_callback)(self.ctx, *target);
         }
     }
 
     fn is_mathml_annotation_xml_integration_point(&self, target: &Ref) -> bool {
+        if Self::is_dropped(target) {
+            return false;
+        }
         let opaque = unsafe { (self.get_data_callback)(*target) };
         let data = opaque as *mut ElementData;
         return unsafe { (*data).mathml_annotation_xml_integration_point };
     }
 
     fn pop(&self, node: &Ref) {
+        if Self::is_dropped(node) {
+            return;
+        }
         unsafe {
             (self.pop_callback)(self.ctx, *node);
         }
     }
 
     fn create_element(&self, name: QualName, attrs: Vec<Attribute>, flags: ElementFlags) -> Ref {
+        let mut name = name;
+        let mut attrs = attrs;
+
+        if let Some(filter_callback) = self.filter_callback {
+            let mut attribute_iterator = CAttributeIterator { vec: attrs, pos: 0 };
+            let mut c_name = CQualName::create(&name);
+            let action = unsafe {
+                filter_callback(
+                    self.ctx,
+                    &mut c_name as *mut _,
+                    &mut attribute_iterator as *mut _ as *mut c_void,
+                )
+            };
+            attrs = attribute_iterator.vec;
+
+            if action == FILTER_DROP {
+                return DROPPED;
+            }
+            if action == FILTER_REWRITE {
+                name = unsafe { c_name.to_qual_name() };
+            }
+        }
+
         let data = self.arena.alloc(ElementData::new(name.clone(), flags));
 
         unsafe {
@@ -153,45 +288,21 @@ impl<'arena> TreeSink for Sink<'arena> {
     }
 
     fn append(&self, parent: &Ref, child: NodeOrText<Ref>) {
-        match child {
-            NodeOrText::AppendText(ref t) => {
-                // The child exists for the duration of the append_callback call,
-                // but sometimes the memory on the Zig side, in append_callback,
-                // is zeroed. If you try to refactor this code a bit, and do:
-                //   unsafe {
-                //       (self.append_callback)(self.ctx, *parent, CNodeOrText::create(child));
-                //   }
-                // Where CNodeOrText::create returns the property CNodeOrText,
-                // you'll occasionally see that zeroed memory. Makes no sense to
-                // me, but a far as I can tell, this version works.
-                let byte_slice = t.as_ref().as_bytes();
-                let static_slice: &'static [u8] = unsafe {
-                    std::mem::transmute(byte_slice)
-                };
-                unsafe {
-                    (self.append_callback)(self.ctx, *parent, CNodeOrText{
-                        tag: 1,
-                        node: ptr::null(),
-                        text: StringSlice { ptr: static_slice.as_ptr(), len: static_slice.len()},
-                     });
-                };
-            },
-            NodeOrText::AppendNode(node) => {
-               unsafe {
-                    (self.append_callback)(self.ctx, *parent, CNodeOrText{
-                        tag: 0,
-                        node: node,
-                        text: StringSlice::default()
-                    });
-                };
-            }
+        if Self::is_dropped(parent) || Self::child_node_is_dropped(&child) {
+            return;
         }
+        Self::with_node_or_text(child, |packed| unsafe {
+            (self.append_callback)(self.ctx, *parent, packed);
+        });
     }
 
     fn append_before_sibling(&self, sibling: &Ref, child: NodeOrText<Ref>) {
-        _ = sibling;
-        _ = child;
-        panic!("append_before_sibling");
+        if Self::is_dropped(sibling) || Self::child_node_is_dropped(&child) {
+            return;
+        }
+        Self::with_node_or_text(child, |packed| unsafe {
+            (self.insert_before_sibling_callback)(self.ctx, *sibling, packed);
+        });
     }
 
     fn append_based_on_parent_node(
@@ -200,10 +311,17 @@ impl<'arena> TreeSink for Sink<'arena> {
         prev_element: &Ref,
         child: NodeOrText<Ref>,
     ) {
-        _ = element;
-        _ = prev_element;
-        _ = child;
-        panic!("append_based_on_parent_node");
+        if Self::is_dropped(element) || Self::is_dropped(prev_element) || Self::child_node_is_dropped(&child) {
+            return;
+        }
+        Self::with_node_or_text(child, |packed| unsafe {
+            (self.append_based_on_parent_node_callback)(
+                self.ctx,
+                *element,
+                *prev_element,
+                packed,
+            );
+        });
     }
 
     fn append_doctype_to_document(
@@ -221,6 +339,9 @@ impl<'arena> TreeSink for Sink<'arena> {
     }
 
     fn add_attrs_if_missing(&self, target: &Ref, attrs: Vec<Attribute>) {
+        if Self::is_dropped(target) {
+            return;
+        }
         unsafe {
             let mut attribute_iterator = CAttributeIterator { vec: attrs, pos: 0 };
 
@@ -233,14 +354,169 @@ impl<'arena> TreeSink for Sink<'arena> {
     }
 
     fn remove_from_parent(&self, target: &Ref) {
+        if Self::is_dropped(target) {
+            return;
+        }
         unsafe {
             (self.remove_from_parent_callback)(self.ctx, *target);
         }
     }
 
     fn reparent_children(&self, node: &Ref, new_parent: &Ref) {
+        if Self::is_dropped(node) || Self::is_dropped(new_parent) {
+            return;
+        }
         unsafe {
             (self.reparent_children_callback)(self.ctx, *node, *new_parent);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static GET_DATA_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static POP_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static APPEND_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn create_element_cb(
+        _ctx: Ref,
+        _data: *const c_void,
+        _name: CQualName,
+        _attributes: *mut c_void,
+    ) -> Ref {
+        // A handle distinct from both DROPPED and the document.
+        return 0x1000 as Ref;
+    }
+    unsafe extern "C" fn get_data_cb(_handle: Ref) -> *mut c_void {
+        // The scope-check path must never reach this for a dropped handle.
+        GET_DATA_CALLS.fetch_add(1, Ordering::SeqCst);
+        static DATA: OnceLock<ElementData> = OnceLock::new();
+        let data = DATA.get_or_init(|| ElementData::new(
+            QualName::new(None, ns!(html), LocalName::from("p")),
+            ElementFlags::default(),
+        ));
+        return data as *const ElementData as *mut c_void;
+    }
+    unsafe extern "C" fn append_cb(_ctx: Ref, _parent: Ref, _node_or_text: CNodeOrText) {
+        APPEND_CALLS.fetch_add(1, Ordering::SeqCst);
+    }
+    unsafe extern "C" fn insert_before_sibling_cb(_ctx: Ref, _sibling: Ref, _node_or_text: CNodeOrText) {
+        APPEND_CALLS.fetch_add(1, Ordering::SeqCst);
+    }
+    unsafe extern "C" fn append_based_on_parent_node_cb(
+        _ctx: Ref,
+        _element: Ref,
+        _prev_element: Ref,
+        _node_or_text: CNodeOrText,
+    ) {
+        APPEND_CALLS.fetch_add(1, Ordering::SeqCst);
+    }
+    unsafe extern "C" fn parse_error_cb(_ctx: Ref, _str: StringSlice) {}
+    unsafe extern "C" fn pop_cb(_ctx: Ref, _node: Ref) {
+        POP_CALLS.fetch_add(1, Ordering::SeqCst);
+    }
+    unsafe extern "C" fn create_comment_cb(_ctx: Ref, _str: StringSlice) -> Ref {
+        return ptr::null();
+    }
+    unsafe extern "C" fn create_pi_cb(_ctx: Ref, _target: StringSlice, _data: StringSlice) -> Ref {
+        return ptr::null();
+    }
+    unsafe extern "C" fn append_doctype_cb(
+        _ctx: Ref,
+        _name: StringSlice,
+        _public_id: StringSlice,
+        _system_id: StringSlice,
+    ) {}
+    unsafe extern "C" fn add_attrs_if_missing_cb(_ctx: Ref, _target: Ref, _attributes: *mut c_void) {}
+    unsafe extern "C" fn get_template_contents_cb(_ctx: Ref, _target: Ref) -> Ref {
+        return ptr::null();
+    }
+    unsafe extern "C" fn remove_from_parent_cb(_ctx: Ref, _target: Ref) {}
+    unsafe extern "C" fn reparent_children_cb(_ctx: Ref, _node: Ref, _new_parent: Ref) {}
+    // Drops <div> elements and keeps everything else.
+    unsafe extern "C" fn filter_drop_cb(_ctx: Ref, name: *mut CQualName, _attributes: *mut c_void) -> u8 {
+        if (*name).to_qual_name().local == LocalName::from("div") {
+            return FILTER_DROP;
+        }
+        return FILTER_KEEP;
+    }
+
+    fn test_sink<'arena>(arena: Arena<'arena>, filter_callback: Option<FilterCallback>) -> Sink<'arena> {
+        return Sink {
+            ctx: ptr::null(),
+            document: 42 as Ref,
+            arena: arena,
+            quirks_mode: Cell::new(QuirksMode::NoQuirks),
+            pop_callback: pop_cb,
+            append_callback: append_cb,
+            insert_before_sibling_callback: insert_before_sibling_cb,
+            append_based_on_parent_node_callback: append_based_on_parent_node_cb,
+            get_data_callback: get_data_cb,
+            parse_error_callback: parse_error_cb,
+            create_element_callback: create_element_cb,
+            create_comment_callback: create_comment_cb,
+            create_processing_instruction: create_pi_cb,
+            append_doctype_to_document: append_doctype_cb,
+            add_attrs_if_missing_callback: add_attrs_if_missing_cb,
+            get_template_contents_callback: get_template_contents_cb,
+            remove_from_parent_callback: remove_from_parent_cb,
+            reparent_children_callback: reparent_children_cb,
+            filter_callback: filter_callback,
+        };
+    }
+
+    // Regression test for a dropped *container* element (not a leaf): once
+    // create_element hands back DROPPED, html5ever's tree builder still
+    // pushes it onto the open-elements stack and later walks that whole
+    // stack for scope checks (e.g. has_element_in_button_scope), calling
+    // elem_name/is_mathml_annotation_xml_integration_point/same_node/pop on
+    // every entry, dropped ones included, and appending its would-be
+    // children/siblings along the way.
+    #[test]
+    fn dropped_container_survives_scope_check_walk() {
+        let arena = typed_arena::Arena::new();
+        let sink = test_sink(&arena, Some(filter_drop_cb));
+
+        let dropped = sink.create_element(
+            QualName::new(None, ns!(html), LocalName::from("div")),
+            vec![],
+            ElementFlags::default(),
+        );
+        assert!(Sink::is_dropped(&dropped));
+
+        // Children/siblings appended under the dropped container must not
+        // reach the C side.
+        sink.append(&dropped, NodeOrText::AppendText(StrTendril::from("text")));
+        sink.append_before_sibling(&dropped, NodeOrText::AppendText(StrTendril::from("text")));
+        sink.append_based_on_parent_node(&dropped, &dropped, NodeOrText::AppendText(StrTendril::from("text")));
+        assert_eq!(APPEND_CALLS.load(Ordering::SeqCst), 0);
+
+        // Simulate a scope-check walking a stack that still contains the
+        // dropped node alongside a real one.
+        let real = sink.create_element(
+            QualName::new(None, ns!(html), LocalName::from("p")),
+            vec![],
+            ElementFlags::default(),
+        );
+        assert!(!Sink::is_dropped(&real));
+
+        for handle in [&real, &dropped] {
+            let _ = sink.elem_name(handle);
+            let _ = sink.is_mathml_annotation_xml_integration_point(handle);
+        }
+        assert!(!sink.same_node(&dropped, &dropped));
+        assert!(!sink.same_node(&dropped, &real));
+
+        sink.pop(&dropped);
+        sink.pop(&real);
+
+        // None of the above may have reached through to the C side for the
+        // dropped handle: only `real`'s elem_name + is_mathml calls got
+        // through to get_data_callback, and only `real` got popped.
+        assert_eq!(GET_DATA_CALLS.load(Ordering::SeqCst), 2);
+        assert_eq!(POP_CALLS.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright (C) 2023-2025  Lightpanda (Selecy SAS)
+//
+// Francis Bouvier <francis@lightpanda.io>
+// Pierre Tachoire <pierre@lightpanda.io>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::os::raw::c_void;
+
+use crate::types::*;
+
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::states::RawKind;
+use html5ever::tokenizer::{Tag, TagKind, Token, TokenSink, TokenSinkResult};
+
+// Made public so it can be used from lib.rs
+pub struct TokenSinkImpl {
+    pub ctx: Ref,
+    pub character_callback: TokenCharacterCallback,
+    pub start_tag_callback: TokenStartTagCallback,
+    pub end_tag_callback: TokenEndTagCallback,
+    pub comment_callback: TokenCommentCallback,
+    pub doctype_callback: TokenDoctypeCallback,
+    pub parse_error_callback: TokenParseErrorCallback,
+    pub eof_callback: TokenEofCallback,
+}
+
+impl TokenSinkImpl {
+    fn handle_tag(&self, tag: Tag) -> TokenSinkResult<()> {
+        match tag.kind {
+            TagKind::StartTag => {
+                let self_closing: u8 = if tag.self_closing { 1 } else { 0 };
+                let result = unsafe {
+                    let mut attribute_iterator = CAttributeIterator { vec: tag.attrs, pos: 0 };
+                    (self.start_tag_callback)(
+                        self.ctx,
+                        CQualName::create(&tag.name),
+                        self_closing,
+                        &mut attribute_iterator as *mut _ as *mut c_void,
+                    )
+                };
+                return match result {
+                    TOKEN_SINK_RESULT_SCRIPT => TokenSinkResult::Script(()),
+                    TOKEN_SINK_RESULT_PLAINTEXT => TokenSinkResult::Plaintext,
+                    TOKEN_SINK_RESULT_RAWDATA_RCDATA => TokenSinkResult::RawData(RawKind::Rcdata),
+                    TOKEN_SINK_RESULT_RAWDATA_RAWTEXT => TokenSinkResult::RawData(RawKind::Rawtext),
+                    TOKEN_SINK_RESULT_RAWDATA_SCRIPT_DATA => TokenSinkResult::RawData(RawKind::ScriptData),
+                    _ => TokenSinkResult::Continue,
+                };
+            },
+            TagKind::EndTag => {
+                unsafe {
+                    (self.end_tag_callback)(self.ctx, CQualName::create(&tag.name));
+                }
+                return TokenSinkResult::Continue;
+            },
+        }
+    }
+}
+
+fn optional_str(t: &Option<StrTendril>) -> CNullable<StringSlice> {
+    match t {
+        None => CNullable::<StringSlice>::none(),
+        Some(s) => CNullable::<StringSlice>::some(StringSlice { ptr: s.as_ptr(), len: s.len() }),
+    }
+}
+
+impl TokenSink for TokenSinkImpl {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        match token {
+            Token::CharacterTokens(text) => {
+                unsafe {
+                    (self.character_callback)(self.ctx, StringSlice { ptr: text.as_ptr(), len: text.len() });
+                }
+                return TokenSinkResult::Continue;
+            },
+            Token::NullCharacterToken => {
+                unsafe {
+                    (self.character_callback)(self.ctx, StringSlice { ptr: "\0".as_ptr(), len: 1 });
+                }
+                return TokenSinkResult::Continue;
+            },
+            Token::TagToken(tag) => {
+                return self.handle_tag(tag);
+            },
+            Token::CommentToken(text) => {
+                unsafe {
+                    (self.comment_callback)(self.ctx, StringSlice { ptr: text.as_ptr(), len: text.len() });
+                }
+                return TokenSinkResult::Continue;
+            },
+            Token::DoctypeToken(doctype) => {
+                unsafe {
+                    (self.doctype_callback)(
+                        self.ctx,
+                        optional_str(&doctype.name),
+                        optional_str(&doctype.public_id),
+                        optional_str(&doctype.system_id),
+                    );
+                }
+                return TokenSinkResult::Continue;
+            },
+            Token::ParseError(err) => {
+                unsafe {
+                    (self.parse_error_callback)(self.ctx, StringSlice { ptr: err.as_ptr(), len: err.len() });
+                }
+                return TokenSinkResult::Continue;
+            },
+            Token::EOFToken => {
+                unsafe {
+                    (self.eof_callback)(self.ctx);
+                }
+                return TokenSinkResult::Continue;
+            },
+            _ => return TokenSinkResult::Continue,
+        }
+    }
+}
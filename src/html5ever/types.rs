@@ -17,7 +17,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::ptr;
-use html5ever::{QualName, Attribute};
+use html5ever::{LocalName, Namespace, Prefix, QualName, Attribute};
 use std::os::raw::{c_uchar, c_void};
 
 pub type CreateElementCallback = unsafe extern "C" fn(
@@ -51,6 +51,76 @@ pub type ParseErrorCallback = unsafe extern "C" fn(ctx: Ref, str: StringSlice) -
 
 pub type PopCallback = unsafe extern "C" fn(ctx: Ref, node: Ref) -> ();
 
+pub type InsertBeforeSiblingCallback = unsafe extern "C" fn(
+    ctx: Ref,
+    sibling: Ref,
+    node_or_text: CNodeOrText,
+) -> ();
+
+// Mirrors html5ever's own append_based_on_parent_node decision: the
+// implementation should insert `node_or_text` before `prev_element` if
+// `element` already has a parent, or otherwise append it as a child of
+// `element`.
+pub type AppendBasedOnParentNodeCallback = unsafe extern "C" fn(
+    ctx: Ref,
+    element: Ref,
+    prev_element: Ref,
+    node_or_text: CNodeOrText,
+) -> ();
+
+// Tokenizer-only callbacks: a parallel FFI surface over html5ever's Tokenizer
+// that hands the raw token stream to C without building a DOM.
+pub type TokenCharacterCallback = unsafe extern "C" fn(ctx: Ref, str: StringSlice) -> ();
+
+// Returns one of the TOKEN_SINK_RESULT_* codes below, letting the C side pick
+// the next tokenizer state so raw-text elements (script/style/textarea/...)
+// tokenize correctly.
+pub type TokenStartTagCallback = unsafe extern "C" fn(
+    ctx: Ref,
+    name: CQualName,
+    self_closing: u8,
+    attributes: *mut c_void,
+) -> u8;
+
+pub type TokenEndTagCallback = unsafe extern "C" fn(ctx: Ref, name: CQualName) -> ();
+
+pub type TokenCommentCallback = unsafe extern "C" fn(ctx: Ref, str: StringSlice) -> ();
+
+pub type TokenDoctypeCallback = unsafe extern "C" fn(
+    ctx: Ref,
+    name: CNullable<StringSlice>,
+    public_id: CNullable<StringSlice>,
+    system_id: CNullable<StringSlice>,
+) -> ();
+
+pub type TokenParseErrorCallback = unsafe extern "C" fn(ctx: Ref, str: StringSlice) -> ();
+
+pub type TokenEofCallback = unsafe extern "C" fn(ctx: Ref) -> ();
+
+pub const TOKEN_SINK_RESULT_CONTINUE: u8 = 0;
+pub const TOKEN_SINK_RESULT_SCRIPT: u8 = 1;
+pub const TOKEN_SINK_RESULT_PLAINTEXT: u8 = 2;
+pub const TOKEN_SINK_RESULT_RAWDATA_RCDATA: u8 = 3;
+pub const TOKEN_SINK_RESULT_RAWDATA_RAWTEXT: u8 = 4;
+pub const TOKEN_SINK_RESULT_RAWDATA_SCRIPT_DATA: u8 = 5;
+
+// Lets the embedder rewrite the tree as it's built, instead of
+// post-processing a finished DOM: invoked before each element is
+// materialized, with a mutable name and attribute iterator it may edit in
+// place (e.g. rewriting `src` to `data-src`) before returning one of the
+// FILTER_* actions below.
+pub type FilterCallback = unsafe extern "C" fn(
+    ctx: Ref,
+    name: *mut CQualName,
+    attributes: *mut c_void,
+) -> u8;
+
+pub const FILTER_KEEP: u8 = 0;
+// Drop the element and its whole subtree.
+pub const FILTER_DROP: u8 = 1;
+// The callback renamed `name` and/or mutated `attributes`; use them as-is.
+pub const FILTER_REWRITE: u8 = 2;
+
 pub type Ref = *const c_void;
 
 #[repr(C)]
@@ -67,6 +137,14 @@ impl<T: Default> CNullable<T> {
         return Self{tag: 1, value: v};
     }
 }
+impl<T> CNullable<T> {
+    pub fn as_option(&self) -> Option<&T> {
+        if self.tag == 1 {
+            return Some(&self.value);
+        }
+        return None;
+    }
+}
 
 #[repr(C)]
 pub struct Slice<T> {
@@ -103,6 +181,28 @@ impl CQualName {
         };
     }
 }
+impl CQualName {
+    // Rebuilds an owned QualName from FFI-provided string slices, for the
+    // rare cases (e.g. the fragment parsing context element) where a
+    // QualName needs to flow from C into html5ever rather than the other
+    // way around.
+    //
+    // # Safety
+    // `self`'s slices must point at valid UTF-8 and be live for the call.
+    pub unsafe fn to_qual_name(&self) -> QualName {
+        let local = std::slice::from_raw_parts(self.local.ptr, self.local.len);
+        let ns = std::slice::from_raw_parts(self.ns.ptr, self.ns.len);
+        let prefix = self.prefix.as_option().map(|prefix| {
+            let bytes = std::slice::from_raw_parts(prefix.ptr, prefix.len);
+            return Prefix::from(std::str::from_utf8_unchecked(bytes));
+        });
+        return QualName::new(
+            prefix,
+            Namespace::from(std::str::from_utf8_unchecked(ns)),
+            LocalName::from(std::str::from_utf8_unchecked(local)),
+        );
+    }
+}
 impl Default for CQualName {
     fn default() -> Self {
         return Self{